@@ -28,12 +28,32 @@
 rs_allocation inputRawBuffer; // RAW16 buffer of dimensions (raw image stride) * (raw image height)
 rs_allocation intermediateBuffer; // Float32 buffer of dimensions (raw image stride) * (raw image height) * 3
 
+// linearize_Raw and correct_Raw_Defects each run as their own full-frame
+// kernel pass, writing every pixel of these before convert_RAW_To_Intermediate
+// reads any of them. Without that, a correction made while a sample is the
+// current invocation's own center pixel would never be visible to a sibling
+// invocation that reads that same sample as one of its demosaic neighbors,
+// since each invocation otherwise reloads and relinearizes its own patch
+// independently.
+rs_allocation linearizedBuffer; // Float32 buffer, same dimensions as inputRawBuffer; linearized, gain-mapped raw samples
+rs_allocation correctedBuffer; // Float32 buffer, same dimensions as inputRawBuffer; linearizedBuffer after defect/green-eq correction
+
 // Gain map
 bool hasGainMap; // Does gainmap exist?
 rs_allocation gainMap; // Gainmap to apply to linearized raw sensor data.
 uint gainMapWidth;  // The width of the gain map
 uint gainMapHeight;  // The height of the gain map
 
+// Flat-field and dark-frame calibration, captured by the user separately
+// from the embedded opcode gain map (lens shading/dust and thermal/fixed-
+// pattern noise respectively).
+bool hasDarkFrame; // Does a user dark-frame exist?
+rs_allocation darkFrame; // RAW16 buffer, same dimensions as inputRawBuffer, subtracted per channel.
+                         // A real sensor capture (lens cap on, same exposure/ISO), so its values
+                         // already include blackLevelPattern -- bl is not subtracted again on top.
+bool hasFlatField; // Does a user flat-field exist?
+rs_allocation flatField; // Float32 per-channel correction, same dimensions as inputRawBuffer, normalized to its own mean
+
 // Transformations
 rs_matrix3x3 sensorToIntermediate; // Color transform from sensor to XYZ.
 
@@ -45,7 +65,10 @@ uint cfaPattern; // The Color Filter Arrangement pattern used
 ushort4 blackLevelPattern; // Blacklevel to subtract for each channel, given in CFA order
 int whiteLevel;  // Whitelevel of sensor
 float3 neutralPoint; // The camera neutral
+bool highlightRecovery; // Reconstruct channels clipped at whiteLevel from the unclipped ones
 float4 toneMapCoeffs; // Coefficients for a polynomial tonemapping curve
+int tonemapOperator; // 0 = polynomial (toneMapCoeffs), 1 = Hable filmic, 2 = Reinhard, 3 = Mobius
+float tonemapKnee; // Fraction of scenePeak below which the Mobius curve stays linear
 
 // Size
 uint offsetX; // X offset into inputRawBuffer
@@ -61,32 +84,79 @@ float saturationFactor;
 float sharpenFactor;
 float histoFactor;
 
+// Denoise mode: 0 = legacy directional run-length walk, 1 = full-window
+// bilateral average, 2 = small-window non-local-means.
+int denoiseMode;
+float denoiseFactor; // Derives the bilateral/NLM spatial sigma: sigmaS = max(denoiseFactor * radiusDenoiseFast, 1)
+
+// Demosaic mode: 0 = bilinear, 1 = Malvar-He-Cutler gradient-corrected linear filter.
+int demosaicMode;
+
+// Green equilibration: corrects the Gr/Gb response mismatch that produces a
+// maze/labyrinth pattern on some sensors, before demosaic() runs.
+bool hasGreenEqualization;
+float greenEqThreshold; // Relative Gr/Gb difference (fraction of local mean) that triggers a pull
+float greenEqStrength; // How far to pull the pixel toward the local green mean, 0..1
+
+// Hot/dead pixel correction: replaces an impulse outlier with the median of
+// its same-color neighbors before demosaic() runs.
+bool hasDefectCorrection;
+float defectThreshold; // Multiplier on the local same-color spread that triggers a correction
+float defectNoiseFloor; // Minimum spread assumed, so flat regions aren't falsely flagged
+
 // Constants
 const static uint radius = 1;
 const static uint size = 2 * radius + 1;
 const static uint area = size * size;
 const static uint midIndex = area / 2;
 
+// Radius of the raw CFA patch loaded around each pixel for defect/green-eq
+// correction and demosaic. The Malvar-He-Cutler filter needs the gradient term two sites out,
+// so this is wider than the postprocessing radius above.
+const static uint demosaicRadius = 2;
+const static uint demosaicSize = 2 * demosaicRadius + 1;
+const static uint demosaicArea = demosaicSize * demosaicSize;
+
 // Cap denoise radius to prevent long processing times.
 const static uint radiusDenoise = 35;
 
+// bilateralDenoise/nlmDenoise visit every candidate in their window
+// unconditionally (no early exit like the legacy walk's threshold break), so
+// they use a much smaller radius than radiusDenoise to stay within the same
+// compute budget: 13x13 = 169 candidates for bilateral, and nlmDenoise
+// additionally compares a 9-sample patch per candidate on top of that.
+const static uint radiusDenoiseFast = 6;
+
 const static uint histogram_slices = 4096;
 
+// Percentile of the intermediate.z histogram used as the adaptive tonemapping
+// peak, so a handful of bright outliers don't crush the rest of the image.
+const static float scenePeakPercentile = 0.995f;
+
 // Histogram
 uint histogram[histogram_slices];
 float remapArray[histogram_slices];
+float scenePeak; // intermediate.z value at scenePeakPercentile, set by create_remap_array
 
 void init() {
-    maxX = rawWidth - 2;
-    maxY = rawHeight - 2;
+    maxX = rawWidth - 1 - demosaicRadius;
+    maxY = rawHeight - 1 - demosaicRadius;
 }
 
 void create_remap_array() {
     uint size = rawWidth * rawHeight;
     uint count = 0;
+    bool peakFound = false;
     for (int i = 0; i < histogram_slices; i++) {
         count += histogram[i];
         remapArray[i] = (float) count / size;
+        if (!peakFound && remapArray[i] >= scenePeakPercentile) {
+            scenePeak = (float) (i + 1) / histogram_slices;
+            peakFound = true;
+        }
+    }
+    if (!peakFound) {
+        scenePeak = 1.f;
     }
 }
 
@@ -126,6 +196,48 @@ static float3 gammaCorrectPixel(float3 rgb) {
     return ret;
 }
 
+// Apply the Hable (Uncharted 2) filmic curve, normalized so peak maps to 1.
+static float2 tonemapHable(float2 s, float peak) {
+    float2 curve = (s * (s * 0.15f + 0.10f * 0.50f) + 0.20f * 0.02f) /
+            (s * (s * 0.15f + 0.50f) + 0.20f * 0.30f) - 0.02f / 0.30f;
+    float peakCurve = (peak * (peak * 0.15f + 0.10f * 0.50f) + 0.20f * 0.02f) /
+            (peak * (peak * 0.15f + 0.50f) + 0.20f * 0.30f) - 0.02f / 0.30f;
+    return curve / peakCurve;
+}
+
+// Apply the Reinhard curve, rescaled so peak maps to 1.
+static float2 tonemapReinhard(float2 s, float peak) {
+    return (s / (s + 1.f)) * ((peak + 1.f) / peak);
+}
+
+// Mobius curve for a single value: linear below knee, Reinhard-like above,
+// continuous and mapping peak to 1. The closed-form a/b solution holds for
+// any peak != 1, including peak < 1 (the normal case here, since scenePeak
+// is a percentile of the 0..1-normalized intermediate.z histogram, not an
+// HDR-headroom ratio above 1). Only the exact peak == 1 singularity needs
+// guarding, so the epsilon floor on (peak - 1) must preserve its sign --
+// flooring it to a bare positive epsilon (as a naive fmax would) corrupts b
+// for every peak < 1 and leaves the curve uncompressed.
+static float mobiusCurve(float s, float peak, float knee) {
+    if (s <= knee) {
+        return s;
+    }
+    float a = -knee * knee * (peak - 1.f) / (knee * knee - 2.f * knee + peak);
+    float peakDelta = peak - 1.f;
+    float safePeakDelta = fabs(peakDelta) < 1e-6f ? copysign(1e-6f, peakDelta) : peakDelta;
+    float b = (knee * knee - 2.f * knee * peak + peak) / safePeakDelta;
+    return (b * b + 2.f * b * knee + knee * knee) / (b - a) * (s + a) / (s + b);
+}
+
+// Apply the Mobius curve to each channel, blending linear and Reinhard-like response.
+static float2 tonemapMobius(float2 s, float peak) {
+    float knee = tonemapKnee * peak;
+    float2 result;
+    result.x = mobiusCurve(s.x, peak, knee);
+    result.y = mobiusCurve(s.y, peak, knee);
+    return result;
+}
+
 // Apply polynomial tonemapping curve to each color channel in RGB pixel.
 // This attempts to apply tonemapping without changing the hue of each pixel,
 // i.e.:
@@ -170,10 +282,23 @@ static float3 tonemap(float3 rgb) {
     minmax.y = sorted.z;
 
     // Apply tonemapping curve to min, max RGB channel values
-    minmax = native_powr(minmax, 3.f) * toneMapCoeffs.x +
-            native_powr(minmax, 2.f) * toneMapCoeffs.y +
-            minmax * toneMapCoeffs.z +
-            toneMapCoeffs.w;
+    switch (tonemapOperator) {
+        case 1: // Hable filmic
+            minmax = tonemapHable(minmax, scenePeak);
+            break;
+        case 2: // Reinhard
+            minmax = tonemapReinhard(minmax, scenePeak);
+            break;
+        case 3: // Mobius
+            minmax = tonemapMobius(minmax, scenePeak);
+            break;
+        default: // Polynomial curve
+            minmax = native_powr(minmax, 3.f) * toneMapCoeffs.x +
+                    native_powr(minmax, 2.f) * toneMapCoeffs.y +
+                    minmax * toneMapCoeffs.z +
+                    toneMapCoeffs.w;
+            break;
+    }
 
     // Rescale middle value
     float newMid;
@@ -245,13 +370,73 @@ static float3 XYZtoxyY(float3 XYZ) {
     return result;
 }
 
+// Threshold, in the whiteLevel/blackLevelPattern-normalized 0..1 space
+// linearizePixel produces, above which a channel is considered clipped.
+const static float highlightClipPoint = 0.99f;
+
+// Recover a channel that saturated at whiteLevel from the channels that
+// didn't, using their ratio to neutralPoint (i.e. how bright this pixel is
+// relative to a neutral target) so recovered highlights stay neutral instead
+// of shifting magenta/cyan. Falls back to neutralPoint when every channel
+// clipped. A recovered channel commonly lands above neutralPoint (that's the
+// point, for a non-neutral-colored highlight), so /*out*/clipCeiling raises
+// the clamp convertSensorToIntermediate applies for any channel this touches
+// from neutralPoint to 1.0 -- the top of the whiteLevel-normalized range --
+// instead of flattening the reconstruction straight back down.
+static float3 reconstructHighlights(float3 sensor, /*out*/ float3* clipCeiling) {
+    *clipCeiling = neutralPoint;
+
+    bool clippedX = sensor.x >= highlightClipPoint;
+    bool clippedY = sensor.y >= highlightClipPoint;
+    bool clippedZ = sensor.z >= highlightClipPoint;
+
+    if (!clippedX && !clippedY && !clippedZ) {
+        return sensor;
+    }
+
+    if (clippedX && clippedY && clippedZ) {
+        return neutralPoint;
+    }
+
+    float ratioSum = 0.f;
+    int ratioCount = 0;
+    if (!clippedX) {
+        ratioSum += sensor.x / neutralPoint.x;
+        ratioCount++;
+    }
+    if (!clippedY) {
+        ratioSum += sensor.y / neutralPoint.y;
+        ratioCount++;
+    }
+    if (!clippedZ) {
+        ratioSum += sensor.z / neutralPoint.z;
+        ratioCount++;
+    }
+    float ratio = ratioSum / ratioCount;
+
+    float3 result = sensor;
+    if (clippedX) {
+        result.x = ratio * neutralPoint.x;
+        clipCeiling->x = 1.f;
+    }
+    if (clippedY) {
+        result.y = ratio * neutralPoint.y;
+        clipCeiling->y = 1.f;
+    }
+    if (clippedZ) {
+        result.z = ratio * neutralPoint.z;
+        clipCeiling->z = 1.f;
+    }
+    return result;
+}
+
 // Color conversion pipeline step one.
-static float3 convertSensorToIntermediate(float3 sensor) {
+static float3 convertSensorToIntermediate(float3 sensor, float3 clipCeiling) {
     float3 intermediate;
 
-    sensor.x = clamp(sensor.x, 0.f, neutralPoint.x);
-    sensor.y = clamp(sensor.y, 0.f, neutralPoint.y);
-    sensor.z = clamp(sensor.z, 0.f, neutralPoint.z);
+    sensor.x = clamp(sensor.x, 0.f, clipCeiling.x);
+    sensor.y = clamp(sensor.y, 0.f, clipCeiling.y);
+    sensor.z = clamp(sensor.z, 0.f, clipCeiling.z);
 
     intermediate = rsMatrixMultiply(&sensorToIntermediate, sensor);
     intermediate = XYZtoxyY(intermediate);
@@ -290,15 +475,16 @@ static float3 applyColorspace(float3 intermediate) {
     return sRGB;
 }
 
-// Load a 3x3 patch of pixels into the output.
-static void load3x3ushort(uint x, uint y, rs_allocation buf, float* outputArray) {
-    ushort3 tmp;
-    int i = 0;
-    while (i < 9) {
-        tmp = rsAllocationVLoadX_ushort3(buf, x - 1, y - 1 + i / 3);
-        outputArray[i++] = tmp.x;
-        outputArray[i++] = tmp.y;
-        outputArray[i++] = tmp.z;
+// Load an NxN patch of single-channel float samples into the output,
+// row-major (index = (yDelta + n / 2) * n + (xDelta + n / 2)), matching the
+// indexing demosaic() expects.
+static void loadNxNfloatScalar(uint x, uint y, int n, rs_allocation buf, /*out*/float* outputArray) {
+    int offset = n / 2;
+    int index = 0;
+    for (int yDelta = -offset; yDelta <= offset; yDelta++) {
+        for (int xDelta = -offset; xDelta <= offset; xDelta++) {
+            outputArray[index++] = *(float *) rsGetElementAt(buf, x + xDelta, y + yDelta);
+        }
     }
 }
 
@@ -314,100 +500,233 @@ static void loadNxNfloat3(uint x, uint y, int n, rs_allocation buf, /*out*/float
     }
 }
 
-// Blacklevel subtract, and normalize each pixel in the outputArray, and apply the
-// gain map.
-static void linearizeAndGainmap(uint x, uint y, ushort4 blackLevel, int whiteLevel,
-        uint cfa, /*inout*/float* outputArray) {
-    uint kk = 0;
-    for (uint j = y - 1; j <= y + 1; j++) {
-        for (uint i = x - 1; i <= x + 1; i++) {
-            uint index = (i & 1) | ((j & 1) << 1);  // bits [0,1] are blacklevel offset
-            index |= (cfa << 2);  // bits [2,3] are cfa
-            float bl = 0.f;
-            float g = 1.f;
-            float4 gains = 1.f;
-            if (hasGainMap) {
-                gains = getGain(i, j);
-            }
-            switch (index) {
-                // RGGB
-                case 0:
-                    bl = blackLevel.x;
-                    g = gains.x;
-                    break;
-                case 1:
-                    bl = blackLevel.y;
-                    g = gains.y;
-                    break;
-                case 2:
-                    bl = blackLevel.z;
-                    g = gains.z;
-                    break;
-                case 3:
-                    bl = blackLevel.w;
-                    g = gains.w;
-                    break;
-                // GRBG
-                case 4:
-                    bl = blackLevel.x;
-                    g = gains.y;
-                    break;
-                case 5:
-                    bl = blackLevel.y;
-                    g = gains.x;
-                    break;
-                case 6:
-                    bl = blackLevel.z;
-                    g = gains.w;
-                    break;
-                case 7:
-                    bl = blackLevel.w;
-                    g = gains.z;
-                    break;
-                // GBRG
-                case 8:
-                    bl = blackLevel.x;
-                    g = gains.y;
-                    break;
-                case 9:
-                    bl = blackLevel.y;
-                    g = gains.w;
-                    break;
-                case 10:
-                    bl = blackLevel.z;
-                    g = gains.x;
-                    break;
-                case 11:
-                    bl = blackLevel.w;
-                    g = gains.z;
-                    break;
-                // BGGR
-                case 12:
-                    bl = blackLevel.x;
-                    g = gains.w;
-                    break;
-                case 13:
-                    bl = blackLevel.y;
-                    g = gains.y;
-                    break;
-                case 14:
-                    bl = blackLevel.z;
-                    g = gains.z;
-                    break;
-                case 15:
-                    bl = blackLevel.w;
-                    g = gains.x;
-                    break;
-            }
+// Blacklevel subtract and normalize a single raw sample, and apply the gain
+// map. Runs as the linearize_Raw kernel, a full-frame pass that completes
+// before correct_Raw_Defects reads any of linearizedBuffer (and so before
+// convert_RAW_To_Intermediate reads correctedBuffer), so every patch read
+// downstream sees already-linearized
+// neighbors instead of each invocation relinearizing its own patch in
+// isolation.
+static float linearizePixel(uint i, uint j, ushort4 blackLevel, int whiteLevel, uint cfa) {
+    uint index = (i & 1) | ((j & 1) << 1);  // bits [0,1] are blacklevel offset
+    index |= (cfa << 2);  // bits [2,3] are cfa
+    float bl = 0.f;
+    float g = 1.f;
+    float4 gains = 1.f;
+    if (hasGainMap) {
+        gains = getGain(i, j);
+    }
+    switch (index) {
+        // RGGB
+        case 0:
+            bl = blackLevel.x;
+            g = gains.x;
+            break;
+        case 1:
+            bl = blackLevel.y;
+            g = gains.y;
+            break;
+        case 2:
+            bl = blackLevel.z;
+            g = gains.z;
+            break;
+        case 3:
+            bl = blackLevel.w;
+            g = gains.w;
+            break;
+        // GRBG
+        case 4:
+            bl = blackLevel.x;
+            g = gains.y;
+            break;
+        case 5:
+            bl = blackLevel.y;
+            g = gains.x;
+            break;
+        case 6:
+            bl = blackLevel.z;
+            g = gains.w;
+            break;
+        case 7:
+            bl = blackLevel.w;
+            g = gains.z;
+            break;
+        // GBRG
+        case 8:
+            bl = blackLevel.x;
+            g = gains.y;
+            break;
+        case 9:
+            bl = blackLevel.y;
+            g = gains.w;
+            break;
+        case 10:
+            bl = blackLevel.z;
+            g = gains.x;
+            break;
+        case 11:
+            bl = blackLevel.w;
+            g = gains.z;
+            break;
+        // BGGR
+        case 12:
+            bl = blackLevel.x;
+            g = gains.w;
+            break;
+        case 13:
+            bl = blackLevel.y;
+            g = gains.y;
+            break;
+        case 14:
+            bl = blackLevel.z;
+            g = gains.z;
+            break;
+        case 15:
+            bl = blackLevel.w;
+            g = gains.x;
+            break;
+    }
 
-            outputArray[kk] = g * (outputArray[kk] - bl) / (whiteLevel - bl);
-            kk++;
-        }
+    float raw = *(ushort *) rsGetElementAt(inputRawBuffer, i, j);
+    if (hasDarkFrame) {
+        // darkFrame is a real sensor capture, so it already bakes in
+        // blackLevelPattern; subtracting bl again here would double
+        // count it and bias the whole image dark.
+        raw -= *(ushort *) rsGetElementAt(darkFrame, i, j);
+        raw = g * raw / (whiteLevel - bl);
+    } else {
+        raw = g * (raw - bl) / (whiteLevel - bl);
     }
+
+    if (hasFlatField) {
+        raw *= *(float *) rsGetElementAt(flatField, i, j);
+    }
+
+    return raw;
 }
 
-// Apply bilinear-interpolation to demosaic
-static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
+// Linearize and gain-map the raw sensor data, one full-frame kernel pass
+// into linearizedBuffer.
+float RS_KERNEL linearize_Raw(uint x, uint y) {
+    return linearizePixel(x, y, blackLevelPattern, whiteLevel, cfaPattern);
+}
+
+// Is the pixel at (x, y) a green CFA site?
+static bool isGreenSite(uint x, uint y, uint cfa) {
+    uint index = (x & 1) | ((y & 1) << 1);
+    index |= (cfa << 2);
+    switch (index) {
+        case 1:
+        case 4:
+        case 11:
+        case 14:
+        case 2:
+        case 7:
+        case 8:
+        case 13:
+            return true;
+        default:
+            return false;
+    }
+}
+
+// Detect and correct a hot/dead pixel by comparing the center sample against
+// the median of its nearest same-color neighbors within the patch. Red/blue
+// same-color neighbors sit two pixels out on each axis; for green, the
+// nearest green neighbors are the diagonal ones a single pixel out,
+// regardless of Gr/Gb subtype.
+static void correctDefectPixel(uint x, uint y, uint cfa, /*inout*/float* patch) {
+    float n0, n1, n2, n3;
+    if (isGreenSite(x, y, cfa)) {
+        n0 = patch[6];
+        n1 = patch[8];
+        n2 = patch[16];
+        n3 = patch[18];
+    } else {
+        n0 = patch[2];
+        n1 = patch[10];
+        n2 = patch[14];
+        n3 = patch[22];
+    }
+
+    float lo1 = fmin(n0, n1), hi1 = fmax(n0, n1);
+    float lo2 = fmin(n2, n3), hi2 = fmax(n2, n3);
+    float median = (fmin(hi1, hi2) + fmax(lo1, lo2)) / 2.f;
+
+    float spread = fmax(fmax(n0, n1), fmax(n2, n3)) - fmin(fmin(n0, n1), fmin(n2, n3));
+    float threshold = defectThreshold * fmax(spread, defectNoiseFloor);
+
+    if (fabs(patch[12] - median) > threshold) {
+        patch[12] = median;
+    }
+}
+
+// Equilibrate the green channel to remove the Gr/Gb "maze" pattern some
+// sensors produce. Compares the green mean interpolated from the center's own
+// green type (same parity, axis distance 2) with the mean of the opposite
+// green type (diagonal neighbors, distance 1); if they differ by more than
+// greenEqThreshold of their average, pulls the center toward that average.
+// Gated on both neighbor sets being internally consistent, so a genuine
+// high-frequency feature (which shows up as large spread within one of the
+// sets) is left alone.
+static void greenEqualize(uint x, uint y, uint cfa, /*inout*/float* patch) {
+    if (!isGreenSite(x, y, cfa)) {
+        return;
+    }
+
+    float ownType = (patch[2] + patch[10] + patch[14] + patch[22]) / 4.f;
+    float oppType = (patch[6] + patch[8] + patch[16] + patch[18]) / 4.f;
+    float localMean = (ownType + oppType) / 2.f;
+    if (localMean <= 0.f) {
+        return;
+    }
+
+    float relDiff = fabs(oppType - ownType) / localMean;
+    if (relDiff <= greenEqThreshold) {
+        return;
+    }
+
+    float diagSpread = fmax(fmax(patch[6], patch[8]), fmax(patch[16], patch[18]))
+            - fmin(fmin(patch[6], patch[8]), fmin(patch[16], patch[18]));
+    float axisSpread = fmax(fmax(patch[2], patch[10]), fmax(patch[14], patch[22]))
+            - fmin(fmin(patch[2], patch[10]), fmin(patch[14], patch[22]));
+    if (diagSpread > greenEqThreshold * localMean || axisSpread > greenEqThreshold * localMean) {
+        return;
+    }
+
+    patch[12] = mix(patch[12], localMean, greenEqStrength);
+}
+
+// Apply defect correction and green equalization, one full-frame kernel pass
+// into correctedBuffer, reading the already-linearized neighborhood from
+// linearizedBuffer. Completing this pass before convert_RAW_To_Intermediate
+// runs means a correction made here is visible to every sibling invocation
+// that reads this sample as a demosaic neighbor, not just the invocation
+// that owned it as its own center pixel.
+float RS_KERNEL correct_Raw_Defects(uint x, uint y) {
+    // Samples too close to the border for a full patch pass through uncorrected,
+    // so every entry of correctedBuffer keeps its own CFA color.
+    if (x < demosaicRadius || y < demosaicRadius || x > maxX || y > maxY) {
+        return *(float *) rsGetElementAt(linearizedBuffer, x, y);
+    }
+
+    float patch[demosaicArea];
+    loadNxNfloatScalar(x, y, demosaicSize, linearizedBuffer, /*out*/ patch);
+
+    if (hasDefectCorrection) {
+        correctDefectPixel(x, y, cfaPattern, /*inout*/patch);
+    }
+
+    if (hasGreenEqualization) {
+        greenEqualize(x, y, cfaPattern, /*inout*/patch);
+    }
+
+    return patch[12];
+}
+
+// Apply bilinear-interpolation to demosaic, reading the 3x3 neighbourhood
+// centered in the wider demosaicArea patch (index 12 is the center pixel).
+static float3 demosaicBilinear(uint x, uint y, uint cfa, float* inputArray) {
     uint index = (x & 1) | ((y & 1) << 1);
     index |= (cfa << 2);
     float3 pRGB;
@@ -419,9 +738,9 @@ static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
                   // B G B
                   // G R G
                   // B G B
-            pRGB.x = inputArray[4];
-            pRGB.y = (inputArray[1] + inputArray[3] + inputArray[5] + inputArray[7]) / 4;
-            pRGB.z = (inputArray[0] + inputArray[2] + inputArray[6] + inputArray[8]) / 4;
+            pRGB.x = inputArray[12];
+            pRGB.y = (inputArray[7] + inputArray[11] + inputArray[13] + inputArray[17]) / 4;
+            pRGB.z = (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18]) / 4;
             break;
         case 1:
         case 4:
@@ -430,9 +749,9 @@ static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
                  // G B G
                  // R G R
                  // G B G
-            pRGB.x = (inputArray[3] + inputArray[5]) / 2;
-            pRGB.y = inputArray[4];
-            pRGB.z = (inputArray[1] + inputArray[7]) / 2;
+            pRGB.x = (inputArray[11] + inputArray[13]) / 2;
+            pRGB.y = inputArray[12];
+            pRGB.z = (inputArray[7] + inputArray[17]) / 2;
             break;
         case 2:
         case 7:
@@ -441,9 +760,9 @@ static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
                  // G R G
                  // B G B
                  // G R G
-            pRGB.x = (inputArray[1] + inputArray[7]) / 2;
-            pRGB.y = inputArray[4];
-            pRGB.z = (inputArray[3] + inputArray[5]) / 2;
+            pRGB.x = (inputArray[7] + inputArray[17]) / 2;
+            pRGB.y = inputArray[12];
+            pRGB.z = (inputArray[11] + inputArray[13]) / 2;
             break;
         case 3:
         case 6:
@@ -452,36 +771,126 @@ static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
                  // R G R
                  // G B G
                  // R G R
-            pRGB.x = (inputArray[0] + inputArray[2] + inputArray[6] + inputArray[8]) / 4;
-            pRGB.y = (inputArray[1] + inputArray[3] + inputArray[5] + inputArray[7]) / 4;
-            pRGB.z = inputArray[4];
+            pRGB.x = (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18]) / 4;
+            pRGB.y = (inputArray[7] + inputArray[11] + inputArray[13] + inputArray[17]) / 4;
+            pRGB.z = inputArray[12];
             break;
     }
     return pRGB;
 }
 
+// Apply the Malvar-He-Cutler gradient-corrected linear filter to demosaic.
+// Each missing channel is the bilinear estimate plus a gradient-correction
+// term taken from the Laplacian of a known channel over the 5x5 patch, which
+// significantly reduces zippering and chroma fringing versus plain bilinear.
+// Indices follow loadNxNfloatScalar's row-major layout; 12 is the center pixel.
+static float3 demosaicMHC(uint x, uint y, uint cfa, float* inputArray) {
+    uint index = (x & 1) | ((y & 1) << 1);
+    index |= (cfa << 2);
+    float3 pRGB;
+    float green, diag, horiz, vert;
+    switch (index) {
+        case 0:
+        case 5:
+        case 10:
+        case 15: // Red centered
+            green = (4.f * inputArray[12]
+                    + 2.f * (inputArray[7] + inputArray[17] + inputArray[11] + inputArray[13])
+                    - (inputArray[2] + inputArray[22] + inputArray[10] + inputArray[14])) / 8.f;
+            diag = (6.f * inputArray[12]
+                    + 2.f * (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18])
+                    - 1.5f * (inputArray[2] + inputArray[10] + inputArray[14] + inputArray[22])) / 8.f;
+            pRGB.x = inputArray[12];
+            pRGB.y = fmax(green, 0.f);
+            pRGB.z = fmax(diag, 0.f);
+            break;
+        case 1:
+        case 4:
+        case 11:
+        case 14: // Green centered w/ horizontally adjacent Red
+            horiz = (0.5f * (inputArray[2] + inputArray[22])
+                    - (inputArray[6] + inputArray[8] + inputArray[10] + inputArray[14] + inputArray[16] + inputArray[18])
+                    + 4.f * (inputArray[11] + inputArray[13])
+                    + 5.f * inputArray[12]) / 8.f;
+            vert = (-(inputArray[2] + inputArray[22])
+                    - (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18])
+                    + 4.f * (inputArray[7] + inputArray[17])
+                    + 0.5f * (inputArray[10] + inputArray[14])
+                    + 5.f * inputArray[12]) / 8.f;
+            pRGB.x = fmax(horiz, 0.f);
+            pRGB.y = inputArray[12];
+            pRGB.z = fmax(vert, 0.f);
+            break;
+        case 2:
+        case 7:
+        case 8:
+        case 13: // Green centered w/ horizontally adjacent Blue
+            vert = (-(inputArray[2] + inputArray[22])
+                    - (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18])
+                    + 4.f * (inputArray[7] + inputArray[17])
+                    + 0.5f * (inputArray[10] + inputArray[14])
+                    + 5.f * inputArray[12]) / 8.f;
+            horiz = (0.5f * (inputArray[2] + inputArray[22])
+                    - (inputArray[6] + inputArray[8] + inputArray[10] + inputArray[14] + inputArray[16] + inputArray[18])
+                    + 4.f * (inputArray[11] + inputArray[13])
+                    + 5.f * inputArray[12]) / 8.f;
+            pRGB.x = fmax(vert, 0.f);
+            pRGB.y = inputArray[12];
+            pRGB.z = fmax(horiz, 0.f);
+            break;
+        case 3:
+        case 6:
+        case 9:
+        case 12: // Blue centered
+            green = (4.f * inputArray[12]
+                    + 2.f * (inputArray[7] + inputArray[17] + inputArray[11] + inputArray[13])
+                    - (inputArray[2] + inputArray[22] + inputArray[10] + inputArray[14])) / 8.f;
+            diag = (6.f * inputArray[12]
+                    + 2.f * (inputArray[6] + inputArray[8] + inputArray[16] + inputArray[18])
+                    - 1.5f * (inputArray[2] + inputArray[10] + inputArray[14] + inputArray[22])) / 8.f;
+            pRGB.x = fmax(diag, 0.f);
+            pRGB.y = fmax(green, 0.f);
+            pRGB.z = inputArray[12];
+            break;
+    }
+    return pRGB;
+}
+
+// Demosaic the loaded CFA patch, dispatching to the selected demosaicMode.
+static float3 demosaic(uint x, uint y, uint cfa, float* inputArray) {
+    return demosaicMode == 1
+            ? demosaicMHC(x, y, cfa, inputArray)
+            : demosaicBilinear(x, y, cfa, inputArray);
+}
+
 static int get_histogram_index(float value) {
     return fmin(floor(value * histogram_slices), histogram_slices - 1);
 }
 
-// Gets unprocessed xyY pixel
-// Do not change processing here.
+// Main per-pixel conversion kernel: loads the corrected CFA patch, demosaics
+// it into sensor-space RGB, reconstructs clipped highlights, and transforms
+// into xyY intermediate space while tallying the scene-peak histogram.
 float3 RS_KERNEL convert_RAW_To_Intermediate(uint x, uint y) {
     float3 sensor, intermediate;
     int histogramIndex;
-    float patch[9];
+    float patch[demosaicArea];
 
     // Ensure within bounds
-    x = max(x, (uint) 1);
-    y = max(y, (uint) 1);
+    x = max(x, demosaicRadius);
+    y = max(y, demosaicRadius);
     x = min(x, maxX);
     y = min(y, maxY);
 
-    load3x3ushort(x, y, inputRawBuffer, /*out*/ patch);
-    linearizeAndGainmap(x, y, blackLevelPattern, whiteLevel, cfaPattern, /*inout*/patch);
+    loadNxNfloatScalar(x, y, demosaicSize, correctedBuffer, /*out*/ patch);
 
     sensor = demosaic(x, y, cfaPattern, patch);
-    intermediate = convertSensorToIntermediate(sensor);
+
+    float3 clipCeiling = neutralPoint;
+    if (highlightRecovery) {
+        sensor = reconstructHighlights(sensor, /*out*/ &clipCeiling);
+    }
+
+    intermediate = convertSensorToIntermediate(sensor, clipCeiling);
 
     histogramIndex = get_histogram_index(intermediate.z);
     rsAtomicInc(&histogram[histogramIndex]);
@@ -491,6 +900,86 @@ float3 RS_KERNEL convert_RAW_To_Intermediate(uint x, uint y) {
 
 // POST PROCESSING STARTS HERE
 
+// Edge-aware average over the radiusDenoiseFast window, weighting each
+// neighbor by exp(-chromaDist^2/sigmaC^2 - spatialDist^2/sigmaS^2). sigmaC is
+// the caller's adaptive, shadow-boosted threshold, so edges are preserved the
+// same way the legacy directional walk preserved them; sigmaS is derived
+// from denoiseFactor.
+static float3 bilateralDenoise(uint x, uint y, float3 px, float sigmaC) {
+    float sigmaS = fmax(denoiseFactor * radiusDenoiseFast, 1.f);
+    float3 weightedSum = px;
+    float weightSum = 1.f;
+
+    int xStart = max((int) x - (int) radiusDenoiseFast, 0);
+    int xEnd = min((int) x + (int) radiusDenoiseFast, (int) rawWidth - 1);
+    int yStart = max((int) y - (int) radiusDenoiseFast, 0);
+    int yEnd = min((int) y + (int) radiusDenoiseFast, (int) rawHeight - 1);
+
+    for (int j = yStart; j <= yEnd; j++) {
+        for (int i = xStart; i <= xEnd; i++) {
+            if (i == (int) x && j == (int) y) {
+                continue;
+            }
+            float3 neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, i, j);
+            float2 chromaDiff = px.xy - neighbour.xy;
+            float chromaDist2 = dot(chromaDiff, chromaDiff);
+            float dx = (float) (i - (int) x);
+            float dy = (float) (j - (int) y);
+            float spatialDist2 = dx * dx + dy * dy;
+            float weight = native_exp(-chromaDist2 / (sigmaC * sigmaC) - spatialDist2 / (sigmaS * sigmaS));
+            weightedSum += neighbour * weight;
+            weightSum += weight;
+        }
+    }
+
+    return weightedSum / weightSum;
+}
+
+// Non-local-means variant of bilateralDenoise: the range term compares the
+// sum of squared xy differences over a 3x3 patch around each candidate
+// against the 3x3 patch around the center, instead of a single-pixel
+// distance. This preserves texture in shadows far better than the
+// single-pixel bilateral weight.
+static float3 nlmDenoise(uint x, uint y, float3 px, float sigmaC) {
+    float sigmaS = fmax(denoiseFactor * radiusDenoiseFast, 1.f);
+    float3 centerPatch[9];
+    loadNxNfloat3(x, y, 3, intermediateBuffer, centerPatch);
+
+    float3 weightedSum = px;
+    float weightSum = 1.f;
+
+    int xStart = max((int) x - (int) radiusDenoiseFast, 0);
+    int xEnd = min((int) x + (int) radiusDenoiseFast, (int) rawWidth - 1);
+    int yStart = max((int) y - (int) radiusDenoiseFast, 0);
+    int yEnd = min((int) y + (int) radiusDenoiseFast, (int) rawHeight - 1);
+
+    for (int j = yStart; j <= yEnd; j++) {
+        for (int i = xStart; i <= xEnd; i++) {
+            if (i == (int) x && j == (int) y) {
+                continue;
+            }
+            float3 candidatePatch[9];
+            loadNxNfloat3(i, j, 3, intermediateBuffer, candidatePatch);
+
+            float chromaDist2 = 0.f;
+            for (int k = 0; k < 9; k++) {
+                float2 diff = centerPatch[k].xy - candidatePatch[k].xy;
+                chromaDist2 += dot(diff, diff);
+            }
+
+            float3 neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, i, j);
+            float dx = (float) (i - (int) x);
+            float dy = (float) (j - (int) y);
+            float spatialDist2 = dx * dx + dy * dy;
+            float weight = native_exp(-chromaDist2 / (9.f * sigmaC * sigmaC) - spatialDist2 / (sigmaS * sigmaS));
+            weightedSum += neighbour * weight;
+            weightSum += weight;
+        }
+    }
+
+    return weightedSum / weightSum;
+}
+
 static float3 processPatch(uint x, uint y) {
     float3 px, neighbour, sum;
     float3 patch[area];
@@ -523,66 +1012,79 @@ static float3 processPatch(uint x, uint y) {
     // Reduce sharpening with high thresholds
     blur = mad(2.f, threshold, 0.8f);
 
-    // Left
-    bound = (int) x - radiusDenoise;
-    bound = max(bound, 0);
-
-    coord = x;
-    while (coord-- > bound) {
-        neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, coord, y);
-        if (fast_distance(px.xy, neighbour.xy) <= threshold) {
-            sum += neighbour;
-            count++;
-        } else {
+    float3 denoised;
+    switch (denoiseMode) {
+        case 1: // Full-window bilateral average
+            denoised = bilateralDenoise(x, y, px, threshold);
             break;
-        }
-    }
-
-    // Right
-    bound = (int) x + radiusDenoise;
-    tmpInt = rawWidth - 1;
-    bound = min(bound, tmpInt);
-
-    coord = x;
-    while (coord++ < bound) {
-        neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, coord, y);
-        if (fast_distance(px.xy, neighbour.xy) <= threshold) {
-            sum += neighbour;
-            count++;
-        } else {
+        case 2: // Small-window non-local-means
+            denoised = nlmDenoise(x, y, px, threshold);
             break;
-        }
-    }
+        default: // Legacy directional run-length walk
+            // Left
+            bound = (int) x - radiusDenoise;
+            bound = max(bound, 0);
+
+            coord = x;
+            while (coord-- > bound) {
+                neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, coord, y);
+                if (fast_distance(px.xy, neighbour.xy) <= threshold) {
+                    sum += neighbour;
+                    count++;
+                } else {
+                    break;
+                }
+            }
 
-    // Up
-    bound = (int) y - radiusDenoise;
-    bound = max(bound, 0);
+            // Right
+            bound = (int) x + radiusDenoise;
+            tmpInt = rawWidth - 1;
+            bound = min(bound, tmpInt);
+
+            coord = x;
+            while (coord++ < bound) {
+                neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, coord, y);
+                if (fast_distance(px.xy, neighbour.xy) <= threshold) {
+                    sum += neighbour;
+                    count++;
+                } else {
+                    break;
+                }
+            }
 
-    coord = y;
-    while (coord-- > bound) {
-        neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, x, coord);
-        if (fast_distance(px.xy, neighbour.xy) <= threshold) {
-            sum += neighbour;
-            count++;
-        } else {
-            break;
-        }
-    }
+            // Up
+            bound = (int) y - radiusDenoise;
+            bound = max(bound, 0);
+
+            coord = y;
+            while (coord-- > bound) {
+                neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, x, coord);
+                if (fast_distance(px.xy, neighbour.xy) <= threshold) {
+                    sum += neighbour;
+                    count++;
+                } else {
+                    break;
+                }
+            }
 
-    // Down
-    bound = (int) y + radiusDenoise;
-    tmpInt = rawHeight - 1;
-    bound = min(bound, tmpInt);
+            // Down
+            bound = (int) y + radiusDenoise;
+            tmpInt = rawHeight - 1;
+            bound = min(bound, tmpInt);
+
+            coord = y;
+            while (coord++ < bound) {
+                neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, x, coord);
+                if (fast_distance(px.xy, neighbour.xy) <= threshold) {
+                    sum += neighbour;
+                    count++;
+                } else {
+                    break;
+                }
+            }
 
-    coord = y;
-    while (coord++ < bound) {
-        neighbour = *(float3 *) rsGetElementAt(intermediateBuffer, x, coord);
-        if (fast_distance(px.xy, neighbour.xy) <= threshold) {
-            sum += neighbour;
-            count++;
-        } else {
+            denoised = sum / count;
             break;
-        }
     }
 
     // Value sharpening
@@ -593,7 +1095,7 @@ static float3 processPatch(uint x, uint y) {
     }
 
     // Get color of patch
-    px = sum / count;
+    px = denoised;
     px.z = clamp(mid + sharpenFactor * tmp / area / blur, 0.f, 1.f);
 
     // Histogram equalization